@@ -18,11 +18,19 @@ pub struct DynamicPolicyRules {
     pub max_per_day: u64,
     pub max_per_week: u64,
     
-    // 時間制約（動的設定可能）
+    // 時間制約（動的設定可能）。単一の連続レンジ＋曜日マスクのみでは
+    // 「平日9-17時＋土曜午前」のような繰り返しスケジュールを表現できないため、
+    // 複数スロットの (曜日マスク, 開始時, 終了時) を追加で持てるようにしてある。
+    // allowed_hours_start/allowed_hours_end/allowed_weekday_mask は
+    // schedule_slot_count == 0 のときに使われる単一スロット相当のフォールバック。
     pub allowed_hours_start: u8,
     pub allowed_hours_end: u8,
     pub allowed_weekday_mask: u8,
-    
+    pub schedule_slot_count: u8,
+    pub schedule_weekday_masks: [u8; SCHEDULE_SLOT_MAX],
+    pub schedule_hour_starts: [u8; SCHEDULE_SLOT_MAX],
+    pub schedule_hour_ends: [u8; SCHEDULE_SLOT_MAX],
+
     // ベンダー制御（動的設定可能）
     pub allowed_vendor_count: u8,
     pub allowed_vendor_hashes: [u64; 10], // 最大10個のベンダー
@@ -40,16 +48,65 @@ pub struct DynamicPolicyRules {
     
     // AI信頼度制約
     pub min_ai_confidence: u64,
+
+    // リスクスコアリング（動的設定可能）: 各違反種別の重み（RISK_WEIGHT_*のインデックス順）
+    pub risk_weights: [u8; RISK_WEIGHT_COUNT],
+    pub risk_threshold: u8,
+}
+
+// 繰り返しスケジュールとして持てる (曜日マスク, 開始時, 終了時) スロットの最大数
+const SCHEDULE_SLOT_MAX: usize = 4;
+
+// risk_weights のインデックス順（applied_rules_mask のビット順と対応）
+const RISK_WEIGHT_COUNT: usize = 11;
+const RISK_WEIGHT_MAX_PER_PAYMENT: usize = 0;
+const RISK_WEIGHT_DAILY_LIMIT: usize = 1;
+const RISK_WEIGHT_WEEKLY_LIMIT: usize = 2;
+const RISK_WEIGHT_VENDOR: usize = 3;
+const RISK_WEIGHT_CATEGORY: usize = 4;
+const RISK_WEIGHT_CONDITION_REJECT: usize = 5;
+const RISK_WEIGHT_CONDITION_REQUIRE_APPROVAL: usize = 6;
+const RISK_WEIGHT_AI_CONFIDENCE: usize = 7;
+const RISK_WEIGHT_TIME_WINDOW: usize = 8;
+const RISK_WEIGHT_WEEKDAY: usize = 9;
+const RISK_WEIGHT_SPENDING_HISTORY_INVALID: usize = 10;
+
+// 三値判定（accept/reject/escalate）。値の大小は深刻度の順序
+// （Approve < RequireApproval < Reject）に対応させてある。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Approve = 0,
+    RequireApproval = 1,
+    Reject = 2,
 }
 
 #[derive(Debug)]
 pub struct DynamicPolicyEvaluation {
-    pub approved: bool,
+    pub decision: Decision,
     pub risk_score: u8,
     pub violation_count: u8,
     pub applied_rules_mask: u64, // どのルールが適用されたかのビットマスク
 }
 
+// 支出履歴のMerkleツリーの深さ（leaf 0 は total_payment_count 用に予約されるため、
+// 最大 2^MERKLE_DEPTH - 1 件の承認済み支払いをコミット可能）
+const MERKLE_DEPTH: usize = 20;
+// ゲスト側で検証する履歴エントリの最大数。ウィンドウ内の分だけでなく、ルートに
+// コミットされた全支払い（total_payment_count件）をこの件数以内で提出できる場合のみ
+// 履歴を信用する。
+const MAX_HISTORY_PAYMENTS: usize = 16;
+
+// ホストがコミットしたMerkleルートに含まれる、過去に承認された支払い1件分の葉。
+// leaf = hash(amount, timestamp, recipient_hash) をルートまで再計算して検証する。
+#[derive(Clone, Debug)]
+pub struct PaymentHistoryLeaf {
+    pub amount: u64,
+    pub timestamp: u64,
+    pub recipient_hash: u64,
+    pub leaf_index: u64,                  // ツリー内の位置（左右の判定に使用）
+    pub siblings: [u64; MERKLE_DEPTH],    // ルートまでの兄弟ノード
+}
+
 fn main() {
     // 動的パラメータを順次読み取り
     console.log("🔐 zkVM Guest: 動的パラメータ読み取り開始");
@@ -71,7 +128,18 @@ fn main() {
     let allowed_hours_start: u8 = env::read();
     let allowed_hours_end: u8 = env::read();
     let allowed_weekday_mask: u8 = env::read();
-    
+
+    // Recurring schedule slots（単一レンジでは表現できない週次スケジュール用）
+    let schedule_slot_count: u8 = env::read();
+    let mut schedule_weekday_masks = [0u8; SCHEDULE_SLOT_MAX];
+    let mut schedule_hour_starts = [0u8; SCHEDULE_SLOT_MAX];
+    let mut schedule_hour_ends = [0u8; SCHEDULE_SLOT_MAX];
+    for i in 0..(schedule_slot_count.min(SCHEDULE_SLOT_MAX as u8) as usize) {
+        schedule_weekday_masks[i] = env::read();
+        schedule_hour_starts[i] = env::read();
+        schedule_hour_ends[i] = env::read();
+    }
+
     console.log("📋 基本ポリシー読み取り完了");
     
     // Dynamic vendor list
@@ -109,12 +177,51 @@ fn main() {
     
     // AI confidence threshold
     let min_ai_confidence: u64 = env::read();
-    
-    // Spending context
-    let current_spending: u64 = env::read();
-    let weekly_spending: u64 = env::read();
-    
-    console.log("💰 支出コンテキスト読み取り完了");
+
+    // Risk scoring parameters（ポリシーごとの重みと自動承認しきい値）
+    let mut risk_weights = [0u8; RISK_WEIGHT_COUNT];
+    for w in risk_weights.iter_mut() {
+        *w = env::read();
+    }
+    let risk_threshold: u8 = env::read();
+
+    console.log("⚖️ リスクスコアリングパラメータ読み取り完了");
+
+    // 支出コンテキスト: Merkleルートと、そのルート下にコミットされた支払い総数
+    // (total_payment_count)を、それぞれinclusion proof付きで受け取る。
+    let committed_spending_root: u64 = env::read();
+    let total_payment_count: u64 = env::read();
+    let mut total_payment_count_siblings = [0u64; MERKLE_DEPTH];
+    for s in total_payment_count_siblings.iter_mut() {
+        *s = env::read();
+    }
+    let history_count: u8 = env::read();
+    let mut spending_history: [PaymentHistoryLeaf; MAX_HISTORY_PAYMENTS] = core::array::from_fn(|_| PaymentHistoryLeaf {
+        amount: 0,
+        timestamp: 0,
+        recipient_hash: 0,
+        leaf_index: 0,
+        siblings: [0u64; MERKLE_DEPTH],
+    });
+    for i in 0..(history_count.min(MAX_HISTORY_PAYMENTS as u8) as usize) {
+        let amount: u64 = env::read();
+        let timestamp: u64 = env::read();
+        let recipient_hash: u64 = env::read();
+        let leaf_index: u64 = env::read();
+        let mut siblings = [0u64; MERKLE_DEPTH];
+        for s in siblings.iter_mut() {
+            *s = env::read();
+        }
+        spending_history[i] = PaymentHistoryLeaf {
+            amount,
+            timestamp,
+            recipient_hash,
+            leaf_index,
+            siblings,
+        };
+    }
+
+    console.log("💰 支出コンテキスト（Merkleコミットメント）読み取り完了");
     
     // 構造体を構築
     let intent = DynamicPaymentIntent {
@@ -133,6 +240,10 @@ fn main() {
         allowed_hours_start,
         allowed_hours_end,
         allowed_weekday_mask,
+        schedule_slot_count,
+        schedule_weekday_masks,
+        schedule_hour_starts,
+        schedule_hour_ends,
         allowed_vendor_count,
         allowed_vendor_hashes,
         category_rules_count,
@@ -143,57 +254,133 @@ fn main() {
         condition_values,
         condition_actions,
         min_ai_confidence,
+        risk_weights,
+        risk_threshold,
     };
     
     console.log("🏗️ 動的構造体構築完了");
-    
+
     // 動的ポリシー評価を実行
-    let evaluation = evaluate_dynamic_policy(&intent, &policy, current_spending, weekly_spending);
-    
+    let evaluation = evaluate_dynamic_policy(
+        &intent,
+        &policy,
+        committed_spending_root,
+        total_payment_count,
+        &total_payment_count_siblings,
+        history_count,
+        &spending_history,
+    );
+
     console.log("✅ 動的ポリシー評価完了");
-    
-    // 結果をコミット
-    env::commit(&(evaluation.approved as u8));
+
+    // 結果をコミット（検証済みのMerkleルートも紐付けのためjournalへ一緒にコミットする）
+    env::commit(&(evaluation.decision as u8));
     env::commit(&evaluation.risk_score);
     env::commit(&evaluation.violation_count);
     env::commit(&evaluation.applied_rules_mask);
+    env::commit(&committed_spending_root);
 }
 
 fn evaluate_dynamic_policy(
     intent: &DynamicPaymentIntent,
     policy: &DynamicPolicyRules,
-    current_spending: u64,
-    weekly_spending: u64,
+    committed_spending_root: u64,
+    total_payment_count: u64,
+    total_payment_count_siblings: &[u64; MERKLE_DEPTH],
+    history_count: u8,
+    spending_history: &[PaymentHistoryLeaf; MAX_HISTORY_PAYMENTS],
 ) -> DynamicPolicyEvaluation {
     let mut violation_count = 0u8;
     let mut risk_score = 0u8;
     let mut applied_rules_mask = 0u64;
+    // hard breach: 即座に Reject を確定させる違反。soft signal: 単独では Reject せず
+    // RequireApproval へのエスカレーションに留める違反。
+    let mut hard_violations = 0u8;
+    let mut soft_violations = 0u8;
 
     console.log("🔍 動的ポリシー評価開始");
 
-    // 1. 基本金額制限チェック（動的パラメータ使用）
+    // 0. 支出履歴のMerkle inclusion proofを検証し、信頼できる日次/週次支出合計を復元する。
+    // total_payment_countも専用leaf(COUNT_LEAF_INDEX)としてルートに固定する。
+    // 「直近MAX_HISTORY_PAYMENTS件」の提出では、1日/1週間にそれ以上の件数の支払いが
+    // 起きていた場合にウィンドウ内の古いleafを取りこぼせてしまうため、total_payment_count
+    // がMAX_HISTORY_PAYMENTSを超える場合はウィンドウの網羅性を証明できないものとして
+    // 履歴そのものを信用しない（全件提出できる場合のみ信用する）。
+    let count_leaf_hash = hash_count_leaf(total_payment_count);
+    let count_leaf_root = merkle_root_from_leaf(count_leaf_hash, COUNT_LEAF_INDEX, total_payment_count_siblings);
+
+    let mut daily_spending = 0u64;
+    let mut weekly_spending = 0u64;
+    let mut spending_history_valid = total_payment_count <= MAX_HISTORY_PAYMENTS as u64
+        && count_leaf_root == committed_spending_root
+        && history_count as u64 == total_payment_count;
+
+    if spending_history_valid {
+        for i in 0..(history_count.min(MAX_HISTORY_PAYMENTS as u8) as usize) {
+            let leaf = &spending_history[i];
+
+            // leaf_index = 1..=total_payment_count の全件を、欠番・並べ替えなく
+            // 提出することを要求する。
+            if leaf.leaf_index != i as u64 + 1 {
+                spending_history_valid = false;
+                break;
+            }
+
+            let leaf_hash = hash_payment_leaf(leaf.amount, leaf.timestamp, leaf.recipient_hash);
+            let recomputed_root = merkle_root_from_leaf(leaf_hash, leaf.leaf_index, &leaf.siblings);
+
+            if recomputed_root != committed_spending_root {
+                spending_history_valid = false;
+                break;
+            }
+
+            if leaf.timestamp <= intent.timestamp && leaf.timestamp >= intent.timestamp.saturating_sub(86_400) {
+                daily_spending = daily_spending.saturating_add(leaf.amount);
+            }
+            if leaf.timestamp <= intent.timestamp && leaf.timestamp >= intent.timestamp.saturating_sub(604_800) {
+                weekly_spending = weekly_spending.saturating_add(leaf.amount);
+            }
+        }
+    }
+
+    if !spending_history_valid {
+        // コミットされた総数・ルートを再現できない、全件を提出できていない、
+        // あるいは提出しきれない件数（>MAX_HISTORY_PAYMENTS）の場合は履歴を
+        // 一切信用しない（hard breach）。
+        violation_count += 1;
+        hard_violations += 1;
+        risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_SPENDING_HISTORY_INVALID]);
+        applied_rules_mask |= 1 << 15; // ビット15: 支出履歴Merkle検証失敗
+    }
+
+    console.log("🌳 支出履歴Merkle inclusion proof検証完了");
+
+    // 1. 基本金額制限チェック（動的パラメータ使用）。金額上限はいずれもhard breach。
     if intent.amount > policy.max_per_payment {
         violation_count += 1;
-        risk_score = risk_score.saturating_add(30);
+        hard_violations += 1;
+        risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_MAX_PER_PAYMENT]);
         applied_rules_mask |= 1; // ビット0: 基本金額制限
     }
 
-    if current_spending + intent.amount > policy.max_per_day {
+    if daily_spending + intent.amount > policy.max_per_day {
         violation_count += 1;
-        risk_score = risk_score.saturating_add(25);
+        hard_violations += 1;
+        risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_DAILY_LIMIT]);
         applied_rules_mask |= 2; // ビット1: 日次制限
     }
 
     if weekly_spending + intent.amount > policy.max_per_week {
         violation_count += 1;
-        risk_score = risk_score.saturating_add(20);
+        hard_violations += 1;
+        risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_WEEKLY_LIMIT]);
         applied_rules_mask |= 4; // ビット2: 週次制限
     }
 
     console.log("💰 基本金額制限チェック完了");
 
     // 2. 動的ベンダーチェック
-    let vendor_allowed = false;
+    let mut vendor_allowed = false;
     for i in 0..(policy.allowed_vendor_count.min(10) as usize) {
         if policy.allowed_vendor_hashes[i] == intent.vendor_hash {
             vendor_allowed = true;
@@ -202,8 +389,10 @@ fn evaluate_dynamic_policy(
     }
     
     if !vendor_allowed && policy.allowed_vendor_count > 0 {
+        // 許可リスト外のベンダーはhard breach。
         violation_count += 1;
-        risk_score = risk_score.saturating_add(25);
+        hard_violations += 1;
+        risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_VENDOR]);
         applied_rules_mask |= 8; // ビット3: ベンダーチェック
     }
 
@@ -213,8 +402,10 @@ fn evaluate_dynamic_policy(
     for i in 0..(policy.category_rules_count.min(5) as usize) {
         if policy.category_hashes[i] == intent.category_hash {
             if intent.amount > policy.category_max_amounts[i] {
+                // カテゴリ別上限も金額制限の一種としてhard breach扱い。
                 violation_count += 1;
-                risk_score = risk_score.saturating_add(20);
+                hard_violations += 1;
+                risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_CATEGORY]);
                 applied_rules_mask |= 16 << i; // ビット4-8: カテゴリルール
             }
             break;
@@ -223,26 +414,35 @@ fn evaluate_dynamic_policy(
 
     console.log("📂 動的カテゴリルールチェック完了");
 
-    // 4. 動的条件分岐ルール
-    for i in 0..(policy.conditional_rules_count.min(5) as usize) {
-        let condition_met = evaluate_condition(
-            policy.condition_types[i],
-            policy.condition_values[i],
-            intent,
-            policy
-        );
-        
-        if condition_met {
-            applied_rules_mask |= 256 << i; // ビット8-12: 条件ルール
-            
-            match policy.condition_actions[i] {
-                2 => { // reject
+    // 4. 動的条件分岐ルール（RPN/スタックマシンによるネスト評価）
+    //
+    // condition_types/condition_values を左から右へ評価する固定長スタックマシンの
+    // プログラムとして扱う。AND/OR/NOTで結合されなかった独立したリーフが複数残った
+    // 場合は、それぞれを個別のルールとしてそのまま評価する（従来の「5個の独立条件」
+    // 挙動と等価）。スタックのunderflow/overflowのみ不正プログラムとして全件falseに倒す。
+    if let Some(program_result) = evaluate_condition_program(
+        &policy.condition_types,
+        &policy.condition_values,
+        policy.conditional_rules_count,
+        intent,
+        policy,
+    ) {
+        for i in 0..program_result.count {
+            if !program_result.values[i] {
+                continue;
+            }
+            let action_index = program_result.action_index[i];
+            applied_rules_mask |= 256 << action_index; // ビット8-12: 条件ルール
+            match policy.condition_actions[action_index] {
+                2 => { // reject -> hard breach
                     violation_count += 1;
-                    risk_score = risk_score.saturating_add(50);
+                    hard_violations += 1;
+                    risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_CONDITION_REJECT]);
                 },
-                3 => { // require_approval
+                3 => { // require_approval -> soft signal
                     violation_count += 1;
-                    risk_score = risk_score.saturating_add(15);
+                    soft_violations += 1;
+                    risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_CONDITION_REQUIRE_APPROVAL]);
                 },
                 _ => {} // approve (1) or unknown
             }
@@ -251,46 +451,218 @@ fn evaluate_dynamic_policy(
 
     console.log("🔀 動的条件ルールチェック完了");
 
-    // 5. AI信頼度チェック
+    // 5. AI信頼度チェック（低信頼度は即reject対象ではなく要確認のsoft signal）
     if intent.ai_confidence < policy.min_ai_confidence {
         violation_count += 1;
-        risk_score = risk_score.saturating_add(10);
+        soft_violations += 1;
+        risk_score = risk_score.saturating_add(policy.risk_weights[RISK_WEIGHT_AI_CONFIDENCE]);
         applied_rules_mask |= 4096; // ビット12: AI信頼度
     }
 
     console.log("🤖 AI信頼度チェック完了");
 
-    // 6. 時間制約チェック
+    // 6-7. 時間帯・曜日スケジュールチェック（複数スロットのいずれかを満たせばOK）。
+    // schedule_slot_count == 0 のときは従来通り単一レンジ＋曜日マスクの判定に委譲する。
     let hour = ((intent.timestamp / 3600) % 24) as u8;
-    if hour < policy.allowed_hours_start || hour >= policy.allowed_hours_end {
-        violation_count += 1;
-        risk_score = risk_score.saturating_add(15);
-        applied_rules_mask |= 8192; // ビット13: 時間制約
-    }
-
-    // 7. 曜日チェック
     let weekday = ((intent.timestamp / 86400 + 4) % 7) as u8;
-    let weekday_bit = 1u8 << weekday;
-    if (policy.allowed_weekday_mask & weekday_bit) == 0 {
+    if !intent_matches_schedule(hour, weekday, policy) {
         violation_count += 1;
-        risk_score = risk_score.saturating_add(10);
+        soft_violations += 1;
+        risk_score = risk_score
+            .saturating_add(policy.risk_weights[RISK_WEIGHT_TIME_WINDOW])
+            .saturating_add(policy.risk_weights[RISK_WEIGHT_WEEKDAY]);
+        applied_rules_mask |= 8192;  // ビット13: 時間制約
         applied_rules_mask |= 16384; // ビット14: 曜日制約
     }
 
     console.log("⏰ 時間・曜日制約チェック完了");
 
-    let approved = violation_count == 0;
+    // hard breachが1つでもあれば即Reject。hard breachが無くsoft signalのみなら
+    // 人間の確認に回すRequireApproval。どちらも無ければクリーンなApprove。
+    let decision = if hard_violations > 0 {
+        Decision::Reject
+    } else if soft_violations > 0 {
+        Decision::RequireApproval
+    } else {
+        Decision::Approve
+    };
+
+    // risk_threshold == 0 は他のカウント系フィールド（allowed_vendor_count等）と同じ
+    // 「0 = 無効」の慣習に合わせ、スコアによる足切りなしとして扱う。
+    let decision = if policy.risk_threshold > 0 && risk_score >= policy.risk_threshold {
+        Decision::Reject
+    } else {
+        decision
+    };
 
     console.log("🏁 動的ポリシー評価完了");
 
     DynamicPolicyEvaluation {
-        approved,
+        decision,
         risk_score: if risk_score > 100 { 100 } else { risk_score },
         violation_count,
         applied_rules_mask,
     }
 }
 
+// intentから導出した時刻(hour)・曜日(weekday)が、いずれかのスケジュールスロットに
+// 合致するかを判定する。スロットが1つも設定されていない場合は、従来の単一レンジ
+// （allowed_hours_start/end + allowed_weekday_mask）をそのスロット1個として扱う
+// 後方互換フォールバックになる。
+fn intent_matches_schedule(hour: u8, weekday: u8, policy: &DynamicPolicyRules) -> bool {
+    let weekday_bit = 1u8 << weekday;
+
+    if policy.schedule_slot_count == 0 {
+        return hour >= policy.allowed_hours_start
+            && hour < policy.allowed_hours_end
+            && (policy.allowed_weekday_mask & weekday_bit) != 0;
+    }
+
+    for i in 0..(policy.schedule_slot_count.min(SCHEDULE_SLOT_MAX as u8) as usize) {
+        let weekday_ok = (policy.schedule_weekday_masks[i] & weekday_bit) != 0;
+        let hour_ok = hour >= policy.schedule_hour_starts[i] && hour < policy.schedule_hour_ends[i];
+        if weekday_ok && hour_ok {
+            return true;
+        }
+    }
+
+    false
+}
+
+// 支出履歴Merkleツリー用の簡易ハッシュ（FNV-1aベースの混合関数）。
+// 専用のハッシュ回路は導入せず、既存コードの recipient_hash/vendor_hash と同じ
+// 「u64へ混合する」スタイルに合わせている。
+fn mix_hash(values: &[u64]) -> u64 {
+    let mut h = 0xcbf29ce484222325u64; // FNV offset basis
+    for &v in values {
+        h ^= v;
+        h = h.wrapping_mul(0x100000001b3u64); // FNV prime
+    }
+    h
+}
+
+fn hash_payment_leaf(amount: u64, timestamp: u64, recipient_hash: u64) -> u64 {
+    mix_hash(&[amount, timestamp, recipient_hash])
+}
+
+// 支払いleafは leaf_index = 1.. に採番されるため、0番は total_payment_count を
+// 固定するための専用leafとして予約する。ドメイン分離用のタグを混ぜて
+// hash_payment_leaf の出力と衝突しないようにしている。
+const COUNT_LEAF_INDEX: u64 = 0;
+const COUNT_LEAF_DOMAIN_TAG: u64 = 0x434f554e545f4c46; // "COUNT_LF"
+
+fn hash_count_leaf(total_payment_count: u64) -> u64 {
+    mix_hash(&[COUNT_LEAF_DOMAIN_TAG, total_payment_count])
+}
+
+fn hash_pair(left: u64, right: u64) -> u64 {
+    mix_hash(&[left, right])
+}
+
+// leaf_indexのビットを下位から辿りながら兄弟ノードと結合し、ルートハッシュを再計算する。
+fn merkle_root_from_leaf(leaf_hash: u64, leaf_index: u64, siblings: &[u64; MERKLE_DEPTH]) -> u64 {
+    let mut node = leaf_hash;
+    let mut idx = leaf_index;
+    for sibling in siblings.iter() {
+        node = if idx & 1 == 0 {
+            hash_pair(node, *sibling)
+        } else {
+            hash_pair(*sibling, node)
+        };
+        idx >>= 1;
+    }
+    node
+}
+
+// ネストした条件式（AND/OR/NOT）をRPNプログラムとして評価するスタックマシン。
+// condition_types をオペコード列として左から右に走査し、リーフ命令(1〜6)は
+// evaluate_condition の結果をスタックにpush、AND(100)/OR(101)/NOT(102)は
+// スタックからオペランドをpopして結果をpushする。
+//
+// スタックに複数の値が残ったまま終端に達した場合（＝AND/OR で結合されていない
+// 独立したリーフが複数ある）は、旧来の「5個の独立ルールをそれぞれ個別に評価する」
+// 挙動として扱う。各残存値に、それを積んだ命令の condition_actions[] を対応付けて
+// 返すのはそのため。スタックのunderflow/overflow（AND/OR/NOTがオペランド不足で
+// 実行された、またはリーフがスタック上限を超えてpushされた）を真の不正プログラム
+// として None を返し、呼び出し側は安全側（全件 false 扱い）とする。
+// 空のプログラム（conditional_rules_count == 0）も評価対象が無いため None を返す。
+// スタックサイズは固定（5）なので zkVM 内でも証明可能な範囲に収まる。
+const CONDITION_STACK_SIZE: usize = 5;
+
+// 条件プログラム評価の結果。スタックに残った値ごとに、それを生成した命令の
+// インデックス（= condition_actions を引くためのキー）を保持する。
+pub struct ConditionProgramResult {
+    pub values: [bool; CONDITION_STACK_SIZE],
+    pub action_index: [usize; CONDITION_STACK_SIZE],
+    pub count: usize,
+}
+
+fn evaluate_condition_program(
+    condition_types: &[u8; 5],
+    condition_values: &[u64; 5],
+    conditional_rules_count: u8,
+    intent: &DynamicPaymentIntent,
+    policy: &DynamicPolicyRules,
+) -> Option<ConditionProgramResult> {
+    let program_len = conditional_rules_count.min(5) as usize;
+    if program_len == 0 {
+        return None;
+    }
+
+    let mut stack = [false; CONDITION_STACK_SIZE];
+    let mut origin = [0usize; CONDITION_STACK_SIZE];
+    let mut sp: usize = 0;
+
+    for i in 0..program_len {
+        match condition_types[i] {
+            100 => { // AND
+                if sp < 2 {
+                    return None; // スタックunderflow
+                }
+                let b = stack[sp - 1];
+                let a = stack[sp - 2];
+                sp -= 2;
+                stack[sp] = a && b;
+                origin[sp] = i;
+                sp += 1;
+            }
+            101 => { // OR
+                if sp < 2 {
+                    return None; // スタックunderflow
+                }
+                let b = stack[sp - 1];
+                let a = stack[sp - 2];
+                sp -= 2;
+                stack[sp] = a || b;
+                origin[sp] = i;
+                sp += 1;
+            }
+            102 => { // NOT
+                if sp < 1 {
+                    return None; // スタックunderflow
+                }
+                let a = stack[sp - 1];
+                stack[sp - 1] = !a;
+                origin[sp - 1] = i;
+            }
+            leaf_opcode => { // 1〜6: 既存のリーフ条件（オペランド）
+                if sp >= CONDITION_STACK_SIZE {
+                    return None; // スタックoverflow
+                }
+                stack[sp] = evaluate_condition(leaf_opcode, condition_values[i], intent, policy);
+                origin[sp] = i;
+                sp += 1;
+            }
+        }
+    }
+
+    Some(ConditionProgramResult {
+        values: stack,
+        action_index: origin,
+        count: sp,
+    })
+}
+
 // 条件評価関数
 fn evaluate_condition(
     condition_type: u8,
@@ -321,3 +693,288 @@ macro_rules! console {
         // zkVM環境では何もしない
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_policy() -> DynamicPolicyRules {
+        DynamicPolicyRules {
+            max_per_payment: u64::MAX,
+            max_per_day: u64::MAX,
+            max_per_week: u64::MAX,
+            allowed_hours_start: 0,
+            allowed_hours_end: 24,
+            allowed_weekday_mask: 0x7F,
+            schedule_slot_count: 0,
+            schedule_weekday_masks: [0; SCHEDULE_SLOT_MAX],
+            schedule_hour_starts: [0; SCHEDULE_SLOT_MAX],
+            schedule_hour_ends: [0; SCHEDULE_SLOT_MAX],
+            allowed_vendor_count: 0,
+            allowed_vendor_hashes: [0; 10],
+            category_rules_count: 0,
+            category_hashes: [0; 5],
+            category_max_amounts: [0; 5],
+            conditional_rules_count: 0,
+            condition_types: [0; 5],
+            condition_values: [0; 5],
+            condition_actions: [0; 5],
+            min_ai_confidence: 0,
+            risk_weights: [0; RISK_WEIGHT_COUNT],
+            risk_threshold: 0,
+        }
+    }
+
+    fn test_intent(amount: u64, vendor_hash: u64) -> DynamicPaymentIntent {
+        DynamicPaymentIntent {
+            amount,
+            recipient_hash: 0,
+            vendor_hash,
+            category_hash: 0,
+            timestamp: 0,
+            ai_confidence: 100,
+        }
+    }
+
+    #[test]
+    fn schedule_matches_when_only_the_second_slot_covers_the_timestamp() {
+        // Weekdays 9-17 (slot 0) plus Saturday mornings 8-12 (slot 1); a Saturday
+        // 10:00 intent only matches slot 1.
+        let mut policy = test_policy();
+        policy.schedule_slot_count = 2;
+        policy.schedule_weekday_masks = [0b0111110, 0b1000000, 0, 0]; // Mon-Fri, Sat
+        policy.schedule_hour_starts = [9, 8, 0, 0];
+        policy.schedule_hour_ends = [17, 12, 0, 0];
+
+        assert!(intent_matches_schedule(10, 6, &policy)); // Saturday 10:00 -> slot 1
+        assert!(!intent_matches_schedule(10, 0, &policy)); // Sunday 10:00 -> no slot
+    }
+
+    #[test]
+    fn schedule_rejects_when_all_slots_fail() {
+        let mut policy = test_policy();
+        policy.schedule_slot_count = 2;
+        policy.schedule_weekday_masks = [0b0111110, 0b1000000, 0, 0]; // Mon-Fri, Sat
+        policy.schedule_hour_starts = [9, 8, 0, 0];
+        policy.schedule_hour_ends = [17, 12, 0, 0];
+
+        // Monday 20:00: weekday matches slot 0 but the hour window doesn't, and
+        // slot 1's weekday doesn't match either.
+        assert!(!intent_matches_schedule(20, 1, &policy));
+    }
+
+    #[test]
+    fn condition_program_empty_is_malformed() {
+        let policy = test_policy();
+        let intent = test_intent(0, 0);
+        assert!(evaluate_condition_program(&[0; 5], &[0; 5], 0, &intent, &policy).is_none());
+    }
+
+    #[test]
+    fn condition_program_and_underflow_is_malformed() {
+        // AND with nothing on the stack yet.
+        let policy = test_policy();
+        let intent = test_intent(0, 0);
+        let types = [100, 0, 0, 0, 0];
+        assert!(evaluate_condition_program(&types, &[0; 5], 1, &intent, &policy).is_none());
+    }
+
+    #[test]
+    fn condition_program_not_underflow_is_malformed() {
+        let policy = test_policy();
+        let intent = test_intent(0, 0);
+        let types = [102, 0, 0, 0, 0];
+        assert!(evaluate_condition_program(&types, &[0; 5], 1, &intent, &policy).is_none());
+    }
+
+    #[test]
+    fn condition_program_five_leaves_exactly_fill_the_stack() {
+        // 5 independent leaves fill the 5-slot stack exactly - still valid.
+        // Real overflow (>5 leaves) isn't reachable with the current fixed-size
+        // condition_types/condition_values: [_; 5] arrays, so it can't be exercised
+        // here; the sp >= CONDITION_STACK_SIZE check exists for when that changes.
+        let policy = test_policy();
+        let intent = test_intent(10, 0);
+        let types = [1, 1, 1, 1, 1];
+        let values = [0u64; 5];
+        let result = evaluate_condition_program(&types, &values, 5, &intent, &policy).unwrap();
+        assert_eq!(result.count, 5);
+    }
+
+    #[test]
+    fn condition_program_nested_and_or() {
+        // (amount > 100 AND vendor == 1) OR ai_confidence < 50
+        let policy = test_policy();
+        let intent = test_intent(200, 1);
+        let types = [1, 4, 100, 3, 101];
+        let values = [100, 1, 0, 50, 0];
+        let result = evaluate_condition_program(&types, &values, 5, &intent, &policy).unwrap();
+        assert_eq!(result.count, 1);
+        assert!(result.values[0]);
+    }
+
+    #[test]
+    fn condition_program_independent_leaves_each_keep_their_own_action() {
+        // Two unrelated leaves with no AND/OR between them: amount > 10, vendor == 1.
+        // This mirrors policies written before the stack machine existed, and must
+        // keep evaluating each leaf (and its own action) independently.
+        let policy = test_policy();
+        let intent = test_intent(20, 1);
+        let types = [1, 4, 0, 0, 0];
+        let values = [10, 1, 0, 0, 0];
+        let result = evaluate_condition_program(&types, &values, 2, &intent, &policy).unwrap();
+        assert_eq!(result.count, 2);
+        assert!(result.values[0] && result.values[1]);
+        assert_eq!(result.action_index[0], 0);
+        assert_eq!(result.action_index[1], 1);
+    }
+
+    #[test]
+    fn independent_reject_leaves_are_not_silently_approved() {
+        // Regression test for the fail-open bug: two independent `reject` leaves
+        // that both evaluate true must still produce a Reject decision, not Approve.
+        let mut policy = test_policy();
+        policy.conditional_rules_count = 2;
+        policy.condition_types = [1, 4, 0, 0, 0];
+        policy.condition_values = [10, 1, 0, 0, 0];
+        policy.condition_actions = [2, 2, 0, 0, 0]; // both reject
+        let intent = test_intent(20, 1);
+
+        let history = core::array::from_fn(|_| PaymentHistoryLeaf {
+            amount: 0,
+            timestamp: 0,
+            recipient_hash: 0,
+            leaf_index: 0,
+            siblings: [0; MERKLE_DEPTH],
+        });
+        let (root, siblings) = empty_history_root();
+        let evaluation =
+            evaluate_dynamic_policy(&intent, &policy, root, 0, &siblings, 0, &history);
+
+        assert_eq!(evaluation.decision, Decision::Reject);
+        assert!(evaluation.violation_count >= 2);
+    }
+
+    #[test]
+    fn zero_risk_threshold_does_not_reject_a_clean_payment() {
+        // risk_threshold: 0 means "unset", not "reject everything" (0 >= 0 would
+        // otherwise reject every payment including a perfectly clean one).
+        let policy = test_policy();
+        let intent = test_intent(1, 0);
+        let history = core::array::from_fn(|_| PaymentHistoryLeaf {
+            amount: 0,
+            timestamp: 0,
+            recipient_hash: 0,
+            leaf_index: 0,
+            siblings: [0; MERKLE_DEPTH],
+        });
+        let (root, siblings) = empty_history_root();
+        let evaluation =
+            evaluate_dynamic_policy(&intent, &policy, root, 0, &siblings, 0, &history);
+        assert_eq!(evaluation.decision, Decision::Approve);
+    }
+
+    #[test]
+    fn merkle_root_from_leaf_matches_hand_computed_root() {
+        // Hand-derive the full MERKLE_DEPTH root for a leaf at index 0 (every path
+        // bit is 0 -> each sibling hashes in as the right-hand side at every level);
+        // only the first two siblings are nonzero.
+        let leaf_hash = hash_payment_leaf(500, 1_700_000_000, 42);
+        let mut siblings = [0u64; MERKLE_DEPTH];
+        siblings[0] = 111;
+        siblings[1] = 222;
+
+        let mut expected = hash_pair(leaf_hash, siblings[0]);
+        expected = hash_pair(expected, siblings[1]);
+        for sibling in siblings.iter().skip(2) {
+            expected = hash_pair(expected, *sibling);
+        }
+
+        let actual = merkle_root_from_leaf(leaf_hash, 0, &siblings);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn omitted_leaf_is_rejected_not_silently_approved() {
+        // The exploit this closes: a host with real payment history submits
+        // history_count = 0 (omitting every leaf) to dodge max_per_day, while
+        // total_payment_count still truthfully commits that payments exist.
+        let mut policy = test_policy();
+        policy.max_per_day = 1_000_000;
+        let intent = test_intent(10, 0);
+
+        let total_payment_count = 3u64;
+        let count_siblings = [0u64; MERKLE_DEPTH];
+        let root = merkle_root_from_leaf(
+            hash_count_leaf(total_payment_count),
+            COUNT_LEAF_INDEX,
+            &count_siblings,
+        );
+
+        let history = core::array::from_fn(|_| PaymentHistoryLeaf {
+            amount: 0,
+            timestamp: 0,
+            recipient_hash: 0,
+            leaf_index: 0,
+            siblings: [0; MERKLE_DEPTH],
+        });
+
+        // history_count = 0 no longer matches total_payment_count = 3, so the
+        // submitted set is rejected as incomplete instead of being trusted as
+        // "no spending".
+        let evaluation =
+            evaluate_dynamic_policy(&intent, &policy, root, total_payment_count, &count_siblings, 0, &history);
+
+        assert_ne!(evaluation.decision, Decision::Approve);
+    }
+
+    #[test]
+    fn fragmented_spend_beyond_history_cap_is_not_silently_undercounted() {
+        // A host that fragments spend into more payments than MAX_HISTORY_PAYMENTS
+        // can attest to must not have its history trusted at all - otherwise it
+        // could always keep daily_spending/weekly_spending understated by staying
+        // just over the cap, defeating max_per_day/max_per_week regardless of the
+        // policy's limits.
+        let mut policy = test_policy();
+        policy.max_per_day = 1_000_000;
+        let intent = test_intent(10, 0);
+
+        let total_payment_count = MAX_HISTORY_PAYMENTS as u64 + 1;
+        let count_siblings = [0u64; MERKLE_DEPTH];
+        let root = merkle_root_from_leaf(
+            hash_count_leaf(total_payment_count),
+            COUNT_LEAF_INDEX,
+            &count_siblings,
+        );
+
+        let history = core::array::from_fn(|_| PaymentHistoryLeaf {
+            amount: 0,
+            timestamp: 0,
+            recipient_hash: 0,
+            leaf_index: 0,
+            siblings: [0; MERKLE_DEPTH],
+        });
+
+        // Even submitting the maximum history_count the array can hold can't
+        // reach total_payment_count here, so the proof is rejected as incomplete.
+        let evaluation = evaluate_dynamic_policy(
+            &intent,
+            &policy,
+            root,
+            total_payment_count,
+            &count_siblings,
+            MAX_HISTORY_PAYMENTS as u8,
+            &history,
+        );
+
+        assert_ne!(evaluation.decision, Decision::Approve);
+    }
+
+    /// Builds a committed root + sibling path for the trivial "zero payments ever
+    /// made" history, for tests that don't care about spend accounting.
+    fn empty_history_root() -> (u64, [u64; MERKLE_DEPTH]) {
+        let siblings = [0u64; MERKLE_DEPTH];
+        let root = merkle_root_from_leaf(hash_count_leaf(0), COUNT_LEAF_INDEX, &siblings);
+        (root, siblings)
+    }
+}